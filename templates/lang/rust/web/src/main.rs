@@ -1,97 +1,341 @@
-use std::{env, sync::Arc};
+use std::{convert::Infallible, env, sync::Arc};
 
+use async_stream::stream;
 use axum::{
     extract::State,
-    response::Html,
+    http::StatusCode,
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     routing::get,
     Router,
 };
 use axum_htmx::HxRequest;
+use futures::Stream;
 use minijinja::{context, Environment};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+#[cfg(not(feature = "embed"))]
 use tower_http::services::ServeDir;
+#[cfg(not(feature = "embed"))]
 use tower_livereload::LiveReloadLayer;
 
+#[cfg(feature = "embed")]
+use axum::{extract::Path, http::header};
+#[cfg(feature = "embed")]
+use rust_embed::RustEmbed;
+
+/// Templates embedded into the binary at compile time. Used by the minijinja
+/// loader in `embed` builds instead of reading from the working directory.
+#[cfg(feature = "embed")]
+#[derive(RustEmbed)]
+#[folder = "templates"]
+struct Templates;
+
+/// Static assets embedded into the binary at compile time, served by
+/// [`handle_static`] in `embed` builds.
+#[cfg(feature = "embed")]
+#[derive(RustEmbed)]
+#[folder = "static"]
+struct StaticFiles;
+
+/// A rendered fragment pushed to SSE subscribers, tagged with the topic it
+/// belongs to so clients can route it to the right htmx target.
+#[derive(Clone, Debug)]
+struct Event {
+    topic: String,
+    html: String,
+}
+
+/// Fan-out channel behind the `/api/events` stream. Handlers call
+/// [`Broadcaster::publish`] to push a rendered fragment to every connected
+/// client; late or slow subscribers simply miss older messages.
+#[derive(Clone)]
+struct Broadcaster {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Broadcaster {
+    fn new() -> Self {
+        let (tx, _rx) = broadcast::channel(64);
+        Self { tx }
+    }
+
+    /// Broadcast a rendered `html` fragment under `topic`. Errors are ignored:
+    /// a send only fails when there are no subscribers, which is fine.
+    fn publish(&self, topic: impl Into<String>, html: impl Into<String>) {
+        let _ = self.tx.send(Event {
+            topic: topic.into(),
+            html: html.into(),
+        });
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
 struct AppState {
     env: Environment<'static>,
+    broadcaster: Broadcaster,
+}
+
+/// Error type returned by handlers. Any `minijinja::Error` (missing template,
+/// render failure) converts into this via `?`, so a broken template yields a
+/// controlled 500 instead of panicking the worker thread.
+#[derive(Debug)]
+enum AppError {
+    Template(minijinja::Error),
+    Internal(String),
+}
+
+impl From<minijinja::Error> for AppError {
+    fn from(err: minijinja::Error) -> Self {
+        AppError::Template(err)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let message = match self {
+            AppError::Template(err) => {
+                eprintln!("template error: {err:#}");
+                "template error"
+            }
+            AppError::Internal(err) => {
+                eprintln!("internal error: {err}");
+                "internal error"
+            }
+        };
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Html(format!("<p class=\"error\">{message}</p>")),
+        )
+            .into_response()
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let port = env::var("PORT").unwrap_or_else(|_| "4000".into());
 
-    let mut env = Environment::new();
-    env.set_loader(minijinja::path_loader("templates"));
+    let state = Arc::new(AppState {
+        env: build_env(),
+        broadcaster: Broadcaster::new(),
+    });
 
-    let state = Arc::new(AppState { env });
+    let app = build_app(state);
 
+    let addr = format!("0.0.0.0:{port}");
+    println!("listening on {addr}");
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+}
+
+/// Assemble the full application router from shared [`AppState`]. Used by both
+/// `main` and the integration tests so they exercise the same middleware chain.
+fn build_app(state: Arc<AppState>) -> Router {
     let app = Router::new()
         .route("/", get(handle_home))
         .route("/api/greet", get(handle_greet))
+        .route("/api/events", get(handle_events));
+
+    // In development we serve templates and assets from disk and reload on
+    // change; the `embed` feature bakes them into the binary instead so the
+    // release build ships as a single self-contained file.
+    #[cfg(feature = "embed")]
+    let app = app.route("/static/{*path}", get(handle_static));
+    #[cfg(not(feature = "embed"))]
+    let app = app
         .nest_service("/static", ServeDir::new("static"))
-        .layer(LiveReloadLayer::new())
-        .with_state(state);
+        .layer(LiveReloadLayer::new());
 
-    let addr = format!("0.0.0.0:{port}");
-    println!("listening on {addr}");
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    app.with_state(state)
 }
 
-async fn handle_home(State(state): State<Arc<AppState>>) -> Html<String> {
-    let tmpl = state.env.get_template("index.html").unwrap();
-    Html(tmpl.render(context! { title => "Home" }).unwrap())
+/// Resolve once the process is asked to stop, either via Ctrl-C or (on Unix) a
+/// `SIGTERM` from a process supervisor. New connections stop being accepted
+/// and in-flight handlers are allowed to finish before the server exits.
+///
+/// Long-lived connections such as the `/api/events` SSE stream would otherwise
+/// keep the shutdown hanging, so we cap the drain at a configurable deadline
+/// (`SHUTDOWN_TIMEOUT_SECS`, default 10s) after which they are force-closed.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    let timeout = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    println!("shutdown signal received, draining for up to {timeout}s");
+
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_secs(timeout)).await;
+        eprintln!("shutdown timeout elapsed, forcing exit");
+        std::process::exit(0);
+    });
+}
+
+/// Build the minijinja environment. In `embed` builds templates are resolved
+/// from the compiled-in [`Templates`]; otherwise they are loaded from the
+/// `templates` directory so edits take effect without a rebuild.
+fn build_env() -> Environment<'static> {
+    let mut env = Environment::new();
+
+    #[cfg(feature = "embed")]
+    env.set_loader(|name| {
+        match Templates::get(name) {
+            Some(file) => {
+                let source = std::str::from_utf8(&file.data)
+                    .map_err(|err| {
+                        minijinja::Error::new(
+                            minijinja::ErrorKind::SyntaxError,
+                            "template is not valid UTF-8",
+                        )
+                        .with_source(err)
+                    })?
+                    .to_owned();
+                Ok(Some(source))
+            }
+            None => Ok(None),
+        }
+    });
+
+    #[cfg(not(feature = "embed"))]
+    env.set_loader(minijinja::path_loader("templates"));
+
+    env
+}
+
+/// Serve an embedded static asset, guessing its content type from the path.
+/// Returns 404 when the requested file is not bundled.
+#[cfg(feature = "embed")]
+async fn handle_static(Path(path): Path<String>) -> Response {
+    match StaticFiles::get(&path) {
+        Some(file) => {
+            let mime = mime_guess::from_path(&path).first_or_octet_stream();
+            ([(header::CONTENT_TYPE, mime.as_ref())], file.data).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn handle_home(State(state): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
+    let tmpl = state.env.get_template("index.html")?;
+    Ok(Html(tmpl.render(context! { title => "Home" })?))
 }
 
 async fn handle_greet(
     HxRequest(is_htmx): HxRequest,
     State(state): State<Arc<AppState>>,
-) -> Html<String> {
+) -> Result<Html<String>, AppError> {
     if is_htmx {
-        Html("<p>Hello from the server!</p>".into())
+        Ok(Html("<p>Hello from the server!</p>".into()))
     } else {
-        let tmpl = state.env.get_template("index.html").unwrap();
-        Html(tmpl.render(context! { title => "Greeting" }).unwrap())
+        let tmpl = state.env.get_template("index.html")?;
+        Ok(Html(tmpl.render(context! { title => "Greeting" })?))
     }
 }
 
+/// Subscribe to the broadcast channel and stream each published fragment as an
+/// SSE message named after its topic. On `Lagged` we log the dropped count and
+/// keep going; the stream ends (dropping the subscriber) when the sender is
+/// gone or the client disconnects.
+async fn handle_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let mut rx = state.broadcaster.subscribe();
+
+    let stream = stream! {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    yield Ok(SseEvent::default().event(event.topic).data(event.html));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("sse subscriber lagged, dropped {skipped} messages");
+                    rx = rx.resubscribe();
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::body::Body;
-    use axum::http::Request;
-    use tower::ServiceExt;
-
-    fn app() -> Router {
-        let mut env = Environment::new();
-        env.set_loader(minijinja::path_loader("templates"));
-        let state = Arc::new(AppState { env });
-
-        Router::new()
-            .route("/", get(handle_home))
-            .route("/api/greet", get(handle_greet))
-            .with_state(state)
+    use axum_test::TestServer;
+
+    fn server() -> TestServer {
+        let state = Arc::new(AppState {
+            env: build_env(),
+            broadcaster: Broadcaster::new(),
+        });
+        TestServer::new(build_app(state)).unwrap()
     }
 
     #[tokio::test]
-    async fn home_returns_html() {
-        let resp = app()
-            .oneshot(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), 200);
+    async fn home_renders_title() {
+        let resp = server().get("/").await;
+        resp.assert_status_ok();
+        resp.assert_text_contains("Home");
     }
 
     #[tokio::test]
-    async fn greet_htmx_returns_fragment() {
-        let resp = app()
-            .oneshot(
-                Request::get("/api/greet")
-                    .header("HX-Request", "true")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), 200);
+    async fn greet_htmx_returns_fragment_only() {
+        let resp = server()
+            .get("/api/greet")
+            .add_header("HX-Request", "true")
+            .await;
+        resp.assert_status_ok();
+        assert_eq!(resp.text(), "<p>Hello from the server!</p>");
+    }
+
+    #[tokio::test]
+    async fn greet_without_header_returns_full_page() {
+        let resp = server().get("/api/greet").await;
+        resp.assert_status_ok();
+        resp.assert_text_contains("Greeting");
+    }
+
+    // The `/api/events` stream is intentionally infinite, so rather than block
+    // on it over HTTP we assert the pub/sub fabric that feeds it: a subscriber
+    // receives fragments published after it connects.
+    #[tokio::test]
+    async fn broadcaster_delivers_published_fragment() {
+        let broadcaster = Broadcaster::new();
+        let mut rx = broadcaster.subscribe();
+        broadcaster.publish("greet", "<p>pushed</p>");
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.topic, "greet");
+        assert_eq!(event.html, "<p>pushed</p>");
     }
 }